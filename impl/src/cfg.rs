@@ -0,0 +1,203 @@
+// Copyright 2021 AgileBits Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Collapses a set of target triples into a `cfg(...)` predicate, so that
+// generated Bazel `select()` conditions read as `cfg(target_os = "linux")`
+// rather than an ever-growing, brittle list of triples.
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::Result;
+
+// A single `rustc --print cfg` atom, e.g. `("target_os", Some("linux"))` or
+// the bare `("unix", None)`.
+type CfgAtom = (String, Option<String>);
+
+// Only these keys are considered when searching for a predicate: they're the
+// ones most likely to be stable, meaningful groupings of a triple list, as
+// opposed to e.g. `target_feature`, which varies with the crate being built.
+const SINGLE_VALUE_KEYS: &[&str] = &[
+  "target_os",
+  "target_arch",
+  "target_family",
+  "target_env",
+  "target_pointer_width",
+];
+const BARE_ATOMS: &[&str] = &["unix", "windows"];
+
+fn rustc_bin_path() -> PathBuf {
+  std::env::var("RUSTC")
+    .map(PathBuf::from)
+    .unwrap_or_else(|_| PathBuf::from("rustc"))
+}
+
+// Runs `rustc --print cfg --target=<triple>` and parses the emitted lines
+// into the set of cfg atoms that apply to that triple.
+fn target_cfg(triple: &str) -> Result<HashSet<CfgAtom>> {
+  let output = Command::new(rustc_bin_path())
+    .arg("--print")
+    .arg("cfg")
+    .arg(format!("--target={}", triple))
+    .output()?;
+
+  let text = String::from_utf8_lossy(&output.stdout);
+  let mut atoms = HashSet::new();
+  for line in text.lines() {
+    match line.split_once('=') {
+      Some((key, value)) => {
+        let value = value.trim().trim_matches('"').to_string();
+        atoms.insert((key.trim().to_string(), Some(value)));
+      }
+      None if !line.trim().is_empty() => {
+        atoms.insert((line.trim().to_string(), None));
+      }
+      None => {}
+    }
+  }
+  Ok(atoms)
+}
+
+// Builds a per-triple cache of `rustc --print cfg` output, so each
+// configured triple is only shelled out to `rustc` once regardless of how
+// many crates/features need a predicate derived for it.
+pub fn cfg_cache<'a>(triples: impl Iterator<Item = &'a String>) -> Result<CfgCache> {
+  let mut cache = HashMap::new();
+  for triple in triples {
+    cache.insert(triple.clone(), target_cfg(triple)?);
+  }
+  Ok(CfgCache(cache))
+}
+
+pub struct CfgCache(HashMap<String, HashSet<CfgAtom>>);
+
+impl CfgCache {
+  fn matches(&self, triple: &str, atom: &CfgAtom) -> bool {
+    self.0.get(triple).is_some_and(|atoms| atoms.contains(atom))
+  }
+
+  // Every single-value or bare atom observed across any cached triple, i.e.
+  // the candidate pool a predicate can be built from.
+  fn candidate_atoms(&self) -> Vec<CfgAtom> {
+    let mut atoms: HashSet<CfgAtom> = HashSet::new();
+    for triple_atoms in self.0.values() {
+      for (key, value) in triple_atoms {
+        let is_candidate = match value {
+          Some(_) => SINGLE_VALUE_KEYS.contains(&key.as_str()),
+          None => BARE_ATOMS.contains(&key.as_str()),
+        };
+        if is_candidate {
+          atoms.insert((key.clone(), value.clone()));
+        }
+      }
+    }
+    let mut atoms: Vec<CfgAtom> = atoms.into_iter().collect();
+    atoms.sort();
+    atoms
+  }
+
+  fn triples_matching(&self, all_triples: &HashSet<String>, atom: &CfgAtom) -> HashSet<String> {
+    all_triples
+      .iter()
+      .filter(|triple| self.matches(triple, atom))
+      .cloned()
+      .collect()
+  }
+
+  // Finds a `cfg(...)` predicate equivalent to `matching` over `all_triples`:
+  // first a single atom that matches `matching` exactly, then a disjunction
+  // of atoms that covers it exactly. The disjunction is built greedily (each
+  // step takes the atom adding the most still-uncovered triples), so it is
+  // not guaranteed to use the fewest possible atoms. Returns `None` when no
+  // exact cover exists, so callers can fall back to the raw triple list.
+  pub fn predicate_for(&self, all_triples: &HashSet<String>, matching: &HashSet<String>) -> Option<String> {
+    let candidates = self.candidate_atoms();
+
+    for atom in &candidates {
+      if self.triples_matching(all_triples, atom) == *matching {
+        return Some(format!("cfg({})", format_atom(atom)));
+      }
+    }
+
+    // Atoms only need to stay within `matching` (not shrink to fit whatever's
+    // still uncovered) and contribute at least one triple nothing chosen so
+    // far covers yet; this lets two overlapping atoms (e.g. one covering
+    // {a,b,c} and another {c,d}) still combine into an exact cover of
+    // {a,b,c,d}, whereas requiring each atom to be a subset of the remaining
+    // uncovered set would reject the second atom for re-covering `c`.
+    let mut covered: HashSet<String> = HashSet::new();
+    let mut chosen: Vec<CfgAtom> = Vec::new();
+    while covered != *matching {
+      let best = candidates
+        .iter()
+        .map(|atom| (atom, self.triples_matching(all_triples, atom)))
+        .filter(|(_, triples)| triples.is_subset(matching) && !triples.is_subset(&covered))
+        .max_by_key(|(_, triples)| triples.difference(&covered).count());
+      match best {
+        Some((atom, triples)) => {
+          chosen.push(atom.clone());
+          covered.extend(triples);
+        }
+        None => return None,
+      }
+    }
+
+    chosen.sort();
+    Some(format!(
+      "cfg(any({}))",
+      chosen.iter().map(format_atom).collect::<Vec<_>>().join(", ")
+    ))
+  }
+}
+
+fn format_atom(atom: &CfgAtom) -> String {
+  match &atom.1 {
+    Some(value) => format!("{} = \"{}\"", atom.0, value),
+    None => atom.0.clone(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn atom(key: &str, value: &str) -> CfgAtom {
+    (key.to_string(), Some(value.to_string()))
+  }
+
+  #[test]
+  fn predicate_for_covers_overlapping_atoms() {
+    // No single atom matches all four triples, but `target_os = "os1"`
+    // ({t1, t2, t3}) and `target_env = "env1"` ({t3, t4}) overlap on t3 and
+    // together cover the full set exactly.
+    let cache = CfgCache(HashMap::from([
+      ("t1".to_string(), HashSet::from([atom("target_os", "os1")])),
+      ("t2".to_string(), HashSet::from([atom("target_os", "os1")])),
+      (
+        "t3".to_string(),
+        HashSet::from([atom("target_os", "os1"), atom("target_env", "env1")]),
+      ),
+      ("t4".to_string(), HashSet::from([atom("target_env", "env1")])),
+    ]));
+
+    let all_triples: HashSet<String> = ["t1", "t2", "t3", "t4"].iter().map(|s| s.to_string()).collect();
+    let matching = all_triples.clone();
+
+    assert_eq!(
+      cache.predicate_for(&all_triples, &matching),
+      Some("cfg(any(target_env = \"env1\", target_os = \"os1\"))".to_string())
+    );
+  }
+}
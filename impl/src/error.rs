@@ -0,0 +1,42 @@
+// Copyright 2021 AgileBits Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub enum RazeError {
+  Generic(String),
+  /// A `cargo` subprocess invocation (e.g. `cargo tree`) exited non-zero.
+  /// Carries enough context to point at the offending target triple and
+  /// cargo's own diagnostics, rather than panicking and losing both.
+  CargoSubcommand {
+    triple: String,
+    stderr: String,
+  },
+}
+
+impl fmt::Display for RazeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RazeError::Generic(message) => write!(f, "{}", message),
+      RazeError::CargoSubcommand { triple, stderr } => write!(
+        f,
+        "cargo invocation failed while resolving features for target '{}': {}",
+        triple, stderr
+      ),
+    }
+  }
+}
+
+impl std::error::Error for RazeError {}
@@ -12,24 +12,32 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
+use crate::cfg::{cfg_cache, CfgCache};
 use crate::error::RazeError;
-use crate::settings::RazeSettings;
+use crate::platform_attribute::{self, PlatformCrateAttribute};
+use crate::settings::{CargoConfig, RazeSettings};
 use crate::util::cargo_bin_path;
 use anyhow::{Error, Result};
 use cargo_metadata::{Package, PackageId, Version};
 use serde::{Deserialize, Serialize};
 
-type UnconsolidatedFeatures = HashMap<PackageId, HashMap<String, HashSet<String>>>;
-
 #[derive(Debug, Clone, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Features {
   pub features: Vec<String>,
   pub targeted_features: Vec<TargetedFeatures>,
+  // Features activated on the crate where it is pulled in as a build
+  // dependency (proc-macro/build-script compilation), merged across all
+  // triples. This is the whole build-edge result: features that are also
+  // active on normal edges are not removed from it. Kept separate from
+  // `features` so the generated rules can feed the host and target
+  // compilations different feature lists.
+  #[serde(default)]
+  pub build_features: Vec<String>,
 }
 
 impl Features {
@@ -37,6 +45,28 @@ impl Features {
     Features {
       features: Vec::new(),
       targeted_features: vec![],
+      build_features: Vec::new(),
+    }
+  }
+}
+
+// Which edges of the dependency graph `cargo tree` should walk. Resolving
+// `Normal` and `Build` edges separately (rather than the old unqualified
+// `cargo tree`, which merges normal, build, and dev edges together) means
+// dev-dependency-only features no longer leak into either result, and
+// host (build-script/proc-macro) feature sets don't get merged with the
+// final target's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CargoTreeEdges {
+  Normal,
+  Build,
+}
+
+impl CargoTreeEdges {
+  fn as_arg(&self) -> &'static str {
+    match self {
+      CargoTreeEdges::Normal => "normal",
+      CargoTreeEdges::Build => "build",
     }
   }
 }
@@ -45,6 +75,22 @@ impl Features {
 pub struct TargetedFeatures {
   pub platforms: Vec<String>,
   pub features: Vec<String>,
+  // A `cfg(...)` predicate equivalent to `platforms`, when one could be
+  // derived (see `crate::cfg`). `None` when no exact single-atom or
+  // disjunction cover exists, in which case consumers should fall back to
+  // the explicit `platforms` list.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub cfg_predicate: Option<String>,
+}
+
+impl PlatformCrateAttribute<String> for TargetedFeatures {
+  fn new(platforms: Vec<String>, features: Vec<String>) -> Self {
+    TargetedFeatures {
+      platforms,
+      features,
+      cfg_predicate: None,
+    }
+  }
 }
 
 // A function that runs `cargo-tree` to analyze per-platform features.
@@ -67,25 +113,111 @@ pub fn get_per_platform_features(
     triples.extend(targets);
   }
 
-  let mut triple_map = HashMap::new();
-  for triple in triples {
-    triple_map.insert(
-      triple.clone(),
-      // TODO: This part is slow, since it runs cargo-tree per-platform.
-      run_cargo_tree(cargo_dir, triple.as_str(), packages)?,
-    );
+  let normal_triple_map = run_cargo_tree_for_triples(
+    cargo_dir,
+    &triples,
+    packages,
+    &settings.cargo_config,
+    CargoTreeEdges::Normal,
+  )?;
+  let build_triple_map = run_cargo_tree_for_triples(
+    cargo_dir,
+    &triples,
+    packages,
+    &settings.cargo_config,
+    CargoTreeEdges::Build,
+  )?;
+
+  let mut build_features_by_pkg: HashMap<PackageId, Vec<String>> = HashMap::new();
+  for pkgs in build_triple_map.into_values() {
+    for (id, feats) in pkgs {
+      let entry = build_features_by_pkg.entry(id).or_default();
+      for feature in feats {
+        if !entry.contains(&feature) {
+          entry.push(feature);
+        }
+      }
+    }
+  }
+  for features in build_features_by_pkg.values_mut() {
+    features.sort();
   }
 
-  let features: Vec<(PackageId, Features)> = transpose_keys(triple_map)
+  let cfg_cache = cfg_cache(triples.iter())?;
+  let normal_features: HashMap<PackageId, Features> = transpose_keys(normal_triple_map)
     .into_iter()
-    .map(consolidate_features)
+    .map(|pkg| consolidate_features(&triples, &cfg_cache, pkg))
     .collect();
-  let mut m = HashMap::new();
-  for f in features {
-    let (id, features) = f;
-    m.insert(id, features);
+  Ok(merge_build_features(normal_features, build_features_by_pkg))
+}
+
+// Attaches each package's build-edge-only features to its normal-edge
+// result. Packages reachable *only* via build edges (the canonical case
+// this split is meant to support, e.g. a proc-macro or build-script-only
+// dependency) have no entry in `normal_features`, so they're seeded here
+// with empty runtime features rather than silently dropped.
+fn merge_build_features(
+  mut normal_features: HashMap<PackageId, Features>,
+  mut build_features_by_pkg: HashMap<PackageId, Vec<String>>,
+) -> HashMap<PackageId, Features> {
+  for (id, features) in normal_features.iter_mut() {
+    features.build_features = build_features_by_pkg.remove(id).unwrap_or_default();
   }
-  Ok(m)
+  for (id, build_features) in build_features_by_pkg {
+    normal_features.insert(
+      id,
+      Features {
+        features: Vec::new(),
+        targeted_features: Vec::new(),
+        build_features,
+      },
+    );
+  }
+  normal_features
+}
+
+// Runs `run_cargo_tree` for every triple in `triples` concurrently, since
+// each invocation is a read-only `--frozen` resolution of the same
+// workspace and they're independent of one another.
+fn run_cargo_tree_for_triples(
+  cargo_dir: &Path,
+  triples: &HashSet<String>,
+  packages: &Vec<Package>,
+  cargo_config: &CargoConfig,
+  edges: CargoTreeEdges,
+) -> Result<HashMap<String, HashMap<PackageId, HashSet<String>>>> {
+  let mut triple_map = HashMap::new();
+  let mut first_err = None;
+  std::thread::scope(|scope| {
+    let handles: Vec<_> = triples
+      .iter()
+      .map(|triple| {
+        let triple = triple.clone();
+        scope.spawn(move || {
+          let result = run_cargo_tree(cargo_dir, triple.as_str(), packages, cargo_config, edges);
+          (triple, result)
+        })
+      })
+      .collect();
+
+    for handle in handles {
+      let (triple, result) = handle.join().expect("cargo-tree worker thread panicked");
+      match result {
+        Ok(features) => {
+          triple_map.insert(triple, features);
+        }
+        Err(err) => {
+          if first_err.is_none() {
+            first_err = Some(err);
+          }
+        }
+      }
+    }
+  });
+  if let Some(err) = first_err {
+    return Err(err);
+  }
+  Ok(triple_map)
 }
 
 // Runs `cargo-tree` with a very specific format argument that makes it easier
@@ -94,9 +226,11 @@ fn run_cargo_tree(
   cargo_dir: &Path,
   triple: &str,
   packages: &Vec<Package>,
+  cargo_config: &CargoConfig,
+  edges: CargoTreeEdges,
 ) -> Result<HashMap<PackageId, HashSet<String>>> {
   // TODO: remove this
-  eprintln!("Run cargo-tree for {}.", triple);
+  eprintln!("Run cargo-tree for {} ({}).", triple, edges.as_arg());
 
   let cargo_bin: PathBuf = cargo_bin_path();
   let mut cargo_tree = Command::new(cargo_bin);
@@ -106,10 +240,26 @@ fn run_cargo_tree(
     .arg("--prefix=none")
     .arg("--frozen")
     .arg(format!("--target={}", triple))
+    .arg(format!("--edges={}", edges.as_arg()))
     .arg("--format={p}|{f}|"); // The format to print output with
 
+  if cargo_config.no_default_features {
+    cargo_tree.arg("--no-default-features");
+  }
+  if cargo_config.all_features {
+    cargo_tree.arg("--all-features");
+  }
+  if !cargo_config.features.is_empty() {
+    cargo_tree.arg(format!("--features={}", cargo_config.features.join(",")));
+  }
+
   let tree_output = cargo_tree.output()?;
-  assert!(tree_output.status.success());
+  if !tree_output.status.success() {
+    return Err(Error::new(RazeError::CargoSubcommand {
+      triple: triple.to_string(),
+      stderr: String::from_utf8_lossy(&tree_output.stderr).into_owned(),
+    }));
+  }
 
   let text = String::from_utf8(tree_output.stdout)?;
   let mut crates: HashSet<String> = HashSet::new();
@@ -183,102 +333,81 @@ fn find_package_id(name: String, version: Version, packages: &Vec<Package>) -> R
     )))
 }
 
-// TODO: this needs to be redone with a BTree and made generic for build targets
-fn transpose_keys(
-  triples: HashMap<String, HashMap<PackageId, HashSet<String>>>,
-) -> UnconsolidatedFeatures {
-  let mut package_map: HashMap<PackageId, HashMap<String, HashSet<String>>> = HashMap::new();
+fn transpose_keys<T: Clone + Eq + Hash>(
+  triples: HashMap<String, HashMap<PackageId, HashSet<T>>>,
+) -> HashMap<PackageId, HashMap<String, HashSet<T>>> {
+  let mut package_map: HashMap<PackageId, HashMap<String, HashSet<T>>> = HashMap::new();
   for (triple, packages) in triples {
-    for (pkg, features) in packages {
-      match package_map.get_mut(&pkg) {
-        Some(triple_map) => {
-          triple_map.insert(triple.clone(), features);
-        },
-        None => {
-          let mut m = HashMap::new();
-          m.insert(triple.clone(), features);
-          package_map.insert(pkg.clone(), m);
-        }
-      }
+    for (pkg, attrs) in packages {
+      package_map.entry(pkg).or_default().insert(triple.clone(), attrs);
     }
   }
   package_map
 }
 
-// TODO: this needs to be redone with a BTree and made generic for build targets
-fn consolidate_features(pkg: (PackageId, HashMap<String, HashSet<String>>)) -> (PackageId, Features) {
-  let (id, features) = pkg;
+fn consolidate_features(
+  all_triples: &HashSet<String>,
+  cfg_cache: &CfgCache,
+  pkg: (PackageId, HashMap<String, HashSet<String>>),
+) -> (PackageId, Features) {
+  let (id, per_platform_features) = pkg;
 
-  // Find the features common to all targets
-  let sets: Vec<&HashSet<String>> = features.values().collect();
-  let common_features = sets.iter().skip(1).fold(sets[0].clone(), |acc, hs| {
-    acc.intersection(hs).cloned().collect()
-  });
+  let (common_features, mut targeted_features): (Vec<String>, Vec<TargetedFeatures>) =
+    platform_attribute::consolidate(per_platform_features);
 
-  // Partition the platform features
-  let mut platform_map: HashMap<String, Vec<String>> = HashMap::new();
-  for (platform, pfs) in features {
-    for feature in pfs {
-      if !common_features.contains(&feature) {
-        match platform_map.get_mut(&feature) {
-          Some(platforms) => {
-            platforms.push(platform.clone());
-          }
-          None => {
-            platform_map.insert(feature, vec![platform.clone()]);
-          }
-        }
-      }
-    }
+  // `consolidate` doesn't know about cfg predicates, so fill them in now
+  // that each bucket's final platform list has been decided.
+  for targeted in &mut targeted_features {
+    let matching: HashSet<String> = targeted.platforms.iter().cloned().collect();
+    targeted.cfg_predicate = cfg_cache.predicate_for(all_triples, &matching);
   }
 
-  let mut platforms_to_features: HashMap<Vec<String>, Vec<String>> = HashMap::new();
-  for (feature, platforms) in platform_map {
-    let mut key = platforms.clone();
-    key.sort();
-    match platforms_to_features.get_mut(&key) {
-      Some(features) => {
-        features.push(feature);
-        features.sort();
-      }
-      None => {
-        platforms_to_features.insert(key, vec![feature]);
-      }
-    }
-  }
-
-  let mut common_vec: Vec<String> = common_features.iter().map(|s| s.clone()).collect();
-  common_vec.sort();
-
-  let mut targeted_features: Vec<TargetedFeatures> = platforms_to_features
-  .iter()
-  .map(|ptf| {
-    let (platforms, features) = ptf;
-    TargetedFeatures {
-      platforms: platforms.to_vec(),
-      features: features.to_vec(),
-    }
-  })
-  .collect();
-
-  // Sort to keep the output stable
-  targeted_features.sort_by(|a, b| {
-    if a.platforms.len() != b.platforms.len() {
-      a.platforms.len().cmp(&b.platforms.len())
-    } else if a.platforms.len() > 0 {
-      a.platforms[0].cmp(&b.platforms[0])
-    } else {
-      Ordering::Equal
-    }
-  });
-  targeted_features.reverse();
-
   (
     id,
     Features {
-      features: common_vec,
+      features: common_features,
       targeted_features,
-    }
+      build_features: Vec::new(),
+    },
   )
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn pkg(repr: &str) -> PackageId {
+    PackageId {
+      repr: repr.to_string(),
+    }
+  }
+
+  #[test]
+  fn merge_build_features_keeps_build_only_package() {
+    // `bitflags` is both a normal dependency and, separately, a
+    // build-script-only dependency (`build-script-only`) that never shows up
+    // in the normal-edge resolution at all.
+    let mut normal_features = HashMap::new();
+    normal_features.insert(pkg("bitflags"), Features::empty());
+
+    let mut build_features_by_pkg = HashMap::new();
+    build_features_by_pkg.insert(pkg("bitflags"), vec!["std".to_string()]);
+    build_features_by_pkg.insert(
+      pkg("build-script-only"),
+      vec!["unstable".to_string()],
+    );
+
+    let merged = merge_build_features(normal_features, build_features_by_pkg);
+
+    assert_eq!(
+      merged.get(&pkg("bitflags")).unwrap().build_features,
+      vec!["std".to_string()]
+    );
+
+    let build_only = merged.get(&pkg("build-script-only")).unwrap();
+    assert!(build_only.features.is_empty());
+    assert!(build_only.targeted_features.is_empty());
+    assert_eq!(build_only.build_features, vec!["unstable".to_string()]);
+  }
+}
  
\ No newline at end of file
@@ -0,0 +1,80 @@
+// Copyright 2021 AgileBits Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Generalizes the "find the intersection common to all platforms, then
+// bucket the remainder by the sorted set of platforms that share it"
+// consolidation that both per-platform feature resolution and per-platform
+// dependency resolution need.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+// Implemented by the per-platform outputs of `consolidate` (e.g.
+// `TargetedFeatures`, or a `CrateTargetedDepContext` for dependency
+// planning) so `consolidate` can stay agnostic of what it's bucketing.
+pub trait PlatformCrateAttribute<T> {
+  fn new(platforms: Vec<String>, attrs: Vec<T>) -> Self;
+}
+
+// Splits `per_platform` (platform -> attributes observed on it) into the
+// attributes common to every platform, and a stably-sorted list of
+// `Out::new(platforms, attrs)` buckets for the attributes that aren't.
+//
+// Buckets are sorted by descending platform-set size, then by descending
+// platform list (first platform name first), to keep output stable across
+// runs.
+pub fn consolidate<T, Out>(per_platform: HashMap<String, HashSet<T>>) -> (Vec<T>, Vec<Out>)
+where
+  T: Clone + Eq + Hash + Ord,
+  Out: PlatformCrateAttribute<T>,
+{
+  let sets: Vec<&HashSet<T>> = per_platform.values().collect();
+  let common: HashSet<T> = match sets.split_first() {
+    Some((first, rest)) => rest
+      .iter()
+      .fold((*first).clone(), |acc, hs| acc.intersection(hs).cloned().collect()),
+    None => HashSet::new(),
+  };
+
+  let mut attr_to_platforms: HashMap<T, Vec<String>> = HashMap::new();
+  for (platform, attrs) in per_platform {
+    for attr in attrs {
+      if !common.contains(&attr) {
+        attr_to_platforms.entry(attr).or_default().push(platform.clone());
+      }
+    }
+  }
+
+  let mut platforms_to_attrs: HashMap<Vec<String>, Vec<T>> = HashMap::new();
+  for (attr, mut platforms) in attr_to_platforms {
+    platforms.sort();
+    let bucket = platforms_to_attrs.entry(platforms).or_default();
+    bucket.push(attr);
+    bucket.sort();
+  }
+
+  let mut common_vec: Vec<T> = common.into_iter().collect();
+  common_vec.sort();
+
+  let mut entries: Vec<(Vec<String>, Vec<T>)> = platforms_to_attrs.into_iter().collect();
+  entries.sort_by(|a, b| a.0.len().cmp(&b.0.len()).then_with(|| a.0.cmp(&b.0)));
+  entries.reverse();
+
+  let targeted: Vec<Out> = entries
+    .into_iter()
+    .map(|(platforms, attrs)| Out::new(platforms, attrs))
+    .collect();
+
+  (common_vec, targeted)
+}
@@ -0,0 +1,38 @@
+// Copyright 2021 AgileBits Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RazeSettings {
+  pub target: Option<String>,
+  pub targets: Option<Vec<String>>,
+  #[serde(default)]
+  pub cargo_config: CargoConfig,
+}
+
+// Mirrors rust-analyzer's `CargoConfig`: the subset of `cargo`'s feature
+// selection flags that change which features are activated during
+// resolution. Threading these through lets cargo-raze reproduce the exact
+// feature set a user's real build uses, rather than always resolving with
+// the default features.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CargoConfig {
+  #[serde(default)]
+  pub no_default_features: bool,
+  #[serde(default)]
+  pub all_features: bool,
+  #[serde(default)]
+  pub features: Vec<String>,
+}
@@ -0,0 +1,24 @@
+// Copyright 2021 AgileBits Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::PathBuf;
+
+// Resolves the `cargo` binary to invoke as a subprocess, honoring the `CARGO`
+// environment variable that cargo itself sets when it is the one driving the
+// build (e.g. `cargo raze`), and falling back to `cargo` on `PATH` otherwise.
+pub fn cargo_bin_path() -> PathBuf {
+  std::env::var("CARGO")
+    .map(PathBuf::from)
+    .unwrap_or_else(|_| PathBuf::from("cargo"))
+}